@@ -43,6 +43,17 @@ pub const DEFAULT_BATCH_SIZE: u8 = 64;
 /// Amount of time between each logged status update.
 pub const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(600);
 
+/// Minimum size (in bytes) a request must be to elicit a response. The default is no smaller
+/// than a full response, so the server cannot be abused as a UDP amplifier.
+pub const DEFAULT_MIN_REQUEST_SIZE: usize = 1024;
+
+/// Number of worker threads, each with its own `SO_REUSEPORT` socket and event loop.
+pub const DEFAULT_NUM_WORKERS: usize = 1;
+
+/// How long a minted online key and its delegation certificate remain in service before the
+/// server rotates to a fresh one. Defaults to one day.
+pub const DEFAULT_ONLINE_KEY_VALIDITY: Duration = Duration::from_secs(60 * 60 * 24);
+
 ///
 /// Specifies parameters needed to configure a Roughenough server.
 ///
@@ -57,6 +68,10 @@ pub const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(600);
 /// `batch_size` | `ROUGHENOUGH_BATCH_SIZE` | Optional | The maximum number of requests to process in one batch. All nonces in a batch are used to build a Merkle tree, the root of which is signed. Default is `64` requests per batch.
 /// `status_interval` | `ROUGHENOUGH_STATUS_INTERVAL` | Optional | Number of _seconds_ between each logged status update. Default is `600` seconds (10 minutes).
 /// `health_check_port` | `ROUGHENOUGH_HEALTH_CHECK_PORT` | Optional | If present, enable an HTTP health check responder on the provided port. **Use with caution**.
+/// `min_request_size` | `ROUGHENOUGH_MIN_REQUEST_SIZE` | Optional | Minimum size in bytes a request must be to elicit a response. Smaller requests are dropped. Default is `1024` bytes, making the server amplification-neutral.
+/// `num_workers` | `ROUGHENOUGH_NUM_WORKERS` | Optional | Number of worker threads, each binding the listen address with `SO_REUSEPORT`. Default is `1`.
+/// `metrics_port` | `ROUGHENOUGH_METRICS_PORT` | Optional | If present, serve Prometheus-format metrics at `GET /metrics` on this TCP port.
+/// `online_key_validity` | `ROUGHENOUGH_ONLINE_KEY_VALIDITY` | Optional | Number of _seconds_ an online key and its certificate stay in service before rotation. Default is `86400` seconds (one day).
 /// `kms_protection` | `ROUGHENOUGH_KMS_PROTECTION` | Optional | If compiled with KMS support, the ID of the KMS key used to protect the long-term identity.
 ///
 /// Implementations of this trait obtain a valid configuration from different back-end
@@ -66,7 +81,7 @@ pub const DEFAULT_STATUS_INTERVAL: Duration = Duration::from_secs(600);
 ///
 /// The health check and KMS features require
 ///
-pub trait ServerConfig {
+pub trait ServerConfig: Send + Sync {
     /// [Required] IP address or interface name to listen for client requests
     fn interface(&self) -> &str;
 
@@ -96,6 +111,26 @@ pub trait ServerConfig {
     /// https://cloud.google.com/load-balancing/docs/health-checks#legacy-health-checks
     fn health_check_port(&self) -> Option<u16>;
 
+    /// [Optional] Minimum size in bytes a request must be before the server will answer it.
+    /// Requests smaller than this are counted as bad requests and dropped, preventing the server
+    /// from acting as a UDP reflection/amplification vector.
+    /// Defaults to [DEFAULT_MIN_REQUEST_SIZE](constant.DEFAULT_MIN_REQUEST_SIZE.html)
+    fn min_request_size(&self) -> usize;
+
+    /// [Optional] Number of worker threads to run. Each worker binds the same `interface:port`
+    /// with `SO_REUSEPORT` and runs an independent event loop, giving near-linear scaling across
+    /// cores. Defaults to [DEFAULT_NUM_WORKERS](constant.DEFAULT_NUM_WORKERS.html)
+    fn num_workers(&self) -> usize;
+
+    /// [Optional] If present, the TCP port on which to serve Prometheus-format metrics at
+    /// `GET /metrics`. This is independent of, and can run alongside, the legacy health check.
+    fn metrics_port(&self) -> Option<u16>;
+
+    /// [Optional] How long each minted online key and its delegation certificate stay in service
+    /// before the server rotates to a fresh one, limiting the exposure of a compromised online
+    /// key. Defaults to [DEFAULT_ONLINE_KEY_VALIDITY](constant.DEFAULT_ONLINE_KEY_VALIDITY.html)
+    fn online_key_validity(&self) -> Duration;
+
     /// Convenience function to create a `SocketAddr` from the provided `interface` and `port`
     fn udp_socket_addr(&self) -> Result<SocketAddr, Error> {
         let addr = format!("{}:{}", self.interface(), self.port());
@@ -159,6 +194,18 @@ pub fn is_valid_config(cfg: &Box<ServerConfig>) -> bool {
         );
         is_valid = false;
     }
+    if cfg.min_request_size() < crate::MIN_REQUEST_LENGTH as usize {
+        error!(
+            "min_request_size {} is below the protocol minimum of {} bytes",
+            cfg.min_request_size(),
+            crate::MIN_REQUEST_LENGTH
+        );
+        is_valid = false;
+    }
+    if cfg.num_workers() < 1 {
+        error!("num_workers must be at least 1");
+        is_valid = false;
+    }
 
     if is_valid {
         match cfg.udp_socket_addr() {
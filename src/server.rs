@@ -17,15 +17,22 @@
 //!
 
 use hex;
-use std::io::ErrorKind;
-use std::net::SocketAddr;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpListener};
 use std::process;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::thread;
+use std::thread::JoinHandle;
 use std::time::Duration;
 use time;
 
-use byteorder::{LittleEndian, WriteBytesExt};
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use net2::unix::UnixUdpBuilderExt;
+use net2::UdpBuilder;
+
+use ring::digest;
 
 use mio::net::UdpSocket;
 use mio::{Events, Poll, PollOpt, Ready, Token};
@@ -49,6 +56,196 @@ macro_rules! check_ctrlc {
 
 const MESSAGE: Token = Token(0);
 const STATUS: Token = Token(1);
+const ROTATE: Token = Token(2);
+
+/// The 8-byte magic string prefacing IETF-draft Roughtime datagrams. When present it is
+/// followed by a little-endian `u32` message length; legacy (Google) requests carry no such
+/// framing and begin directly with the tag count.
+const IETF_FRAME_MAGIC: &[u8] = b"ROUGHTIM";
+
+/// Roughtime draft version word this server understands and negotiates via the `VER` tag.
+const ROUGHTIM_DRAFT_VERSION: u32 = 0x8000_000b;
+
+/// The framing and protocol version negotiated for a single request.
+///
+/// Classic clients speak the legacy Google format (no outer magic, no `VER` tag); draft clients
+/// prefix the [magic](constant.IETF_FRAME_MAGIC.html) and advertise their supported versions so
+/// the server can echo a mutually-agreed one back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Version {
+    /// Legacy Google/classic framing.
+    Classic,
+    /// IETF draft framing, carrying the version word echoed back in the response `VER` tag.
+    Ietf(u32),
+}
+
+/// Upper bounds for the batch-fill histogram (requests signed together in one Merkle tree).
+const BATCH_FILL_BOUNDS: &[u64] = &[1, 2, 4, 8, 16, 32, 64];
+
+/// Upper bounds (in bytes) for the response-size histogram.
+const RESPONSE_BYTES_BOUNDS: &[u64] = &[256, 512, 1024, 2048, 4096, 8192];
+
+/// A cumulative histogram with fixed upper bounds, rendered in Prometheus exposition format.
+struct Histogram {
+    bounds: &'static [u64],
+    buckets: Vec<AtomicU64>,
+    sum: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [u64]) -> Histogram {
+        Histogram {
+            bounds,
+            buckets: bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: u64) {
+        // Each `le` bucket is cumulative, so increment every bucket whose bound the value fits in.
+        for (i, &bound) in self.bounds.iter().enumerate() {
+            if value <= bound {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, out: &mut String) {
+        use std::fmt::Write as _;
+
+        let count = self.count.load(Ordering::Relaxed);
+        for (i, &bound) in self.bounds.iter().enumerate() {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{le=\"{}\"}} {}",
+                name,
+                bound,
+                self.buckets[i].load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, count);
+        let _ = writeln!(out, "{}_sum {}", name, self.sum.load(Ordering::Relaxed));
+        let _ = writeln!(out, "{}_count {}", name, count);
+    }
+}
+
+/// Counters and histograms shared across all workers and exported on the metrics port.
+struct Metrics {
+    responses: AtomicUsize,
+    bad_requests: AtomicUsize,
+    batch_fill: Histogram,
+    response_bytes: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        Metrics {
+            responses: AtomicUsize::new(0),
+            bad_requests: AtomicUsize::new(0),
+            batch_fill: Histogram::new(BATCH_FILL_BOUNDS),
+            response_bytes: Histogram::new(RESPONSE_BYTES_BOUNDS),
+        }
+    }
+
+    // Render the current values in Prometheus text exposition format.
+    fn render(&self) -> String {
+        use std::fmt::Write as _;
+
+        let mut out = String::new();
+
+        let _ = writeln!(
+            out,
+            "# HELP roughenough_responses_total Total responses sent."
+        );
+        let _ = writeln!(out, "# TYPE roughenough_responses_total counter");
+        let _ = writeln!(
+            out,
+            "roughenough_responses_total {}",
+            self.responses.load(Ordering::SeqCst)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP roughenough_bad_requests_total Total requests that failed to parse."
+        );
+        let _ = writeln!(out, "# TYPE roughenough_bad_requests_total counter");
+        let _ = writeln!(
+            out,
+            "roughenough_bad_requests_total {}",
+            self.bad_requests.load(Ordering::SeqCst)
+        );
+
+        let _ = writeln!(
+            out,
+            "# HELP roughenough_batch_fill Requests signed together per batch."
+        );
+        let _ = writeln!(out, "# TYPE roughenough_batch_fill histogram");
+        self.batch_fill.render("roughenough_batch_fill", &mut out);
+
+        let _ = writeln!(
+            out,
+            "# HELP roughenough_response_bytes Size in bytes of responses sent."
+        );
+        let _ = writeln!(out, "# TYPE roughenough_response_bytes histogram");
+        self.response_bytes
+            .render("roughenough_response_bytes", &mut out);
+
+        out
+    }
+}
+
+// Locate the value of `want` by walking the Roughtime tag table, returning `None` if the tag is
+// absent or the message is malformed. Tags may appear in any order, so callers must not assume a
+// fixed offset.
+fn tag_value<'a>(msg: &'a [u8], want: Tag) -> Option<&'a [u8]> {
+    if msg.len() < 4 {
+        return None;
+    }
+
+    let num_tags = LittleEndian::read_u32(&msg[..4]) as usize;
+    if num_tags == 0 {
+        return None;
+    }
+
+    // 4-byte tag count, (num_tags - 1) value end-offsets, then num_tags tag words.
+    let offsets_len = (num_tags - 1) * 4;
+    let header_len = 4 + offsets_len + num_tags * 4;
+    if msg.len() < header_len {
+        return None;
+    }
+
+    let offsets = &msg[4..4 + offsets_len];
+    let tags = &msg[4 + offsets_len..header_len];
+    let values = &msg[header_len..];
+
+    for i in 0..num_tags {
+        if &tags[i * 4..i * 4 + 4] != want.wire_value() {
+            continue;
+        }
+
+        let start = if i == 0 {
+            0
+        } else {
+            LittleEndian::read_u32(&offsets[(i - 1) * 4..i * 4]) as usize
+        };
+        let end = if i == num_tags - 1 {
+            values.len()
+        } else {
+            LittleEndian::read_u32(&offsets[i * 4..i * 4 + 4]) as usize
+        };
+
+        if start > end || end > values.len() {
+            return None;
+        }
+        return Some(&values[start..end]);
+    }
+
+    None
+}
 
 /// The main Roughenough server instance.
 ///
@@ -61,25 +258,34 @@ const STATUS: Token = Token(1);
 /// See [the config module](../config/index.html) for more information.
 ///
 pub struct Server {
-    config: Box<ServerConfig>,
+    config: Arc<ServerConfig>,
+    long_term_key: LongTermKey,
     online_key: OnlineKey,
     cert_bytes: Vec<u8>,
 
-    response_counter: AtomicUsize,
-    num_bad_requests: u64,
+    // Shared across all workers so the STATUS timer and metrics endpoint report aggregate totals.
+    metrics: Arc<Metrics>,
+
+    // Index of this worker; only worker 0 emits the consolidated STATUS line.
+    worker_id: usize,
 
     socket: UdpSocket,
     keep_running: Arc<AtomicBool>,
     poll_duration: Option<Duration>,
     timer: Timer<()>,
+    rotation_timer: Timer<()>,
     poll: Poll,
     events: Events,
     merkle: MerkleTree,
-    requests: Vec<(Vec<u8>, SocketAddr)>,
+    requests: Vec<(Vec<u8>, SocketAddr, Version)>,
     buf: [u8; 65_536],
 
     public_key: String,
 
+    // Expected SRV tag value: a hash of the long-term public key, used by draft clients for
+    // request domain separation.
+    srv_value: Vec<u8>,
+
     // Used to send requests to ourselves in fuzzing mode
     #[cfg(fuzzing)]
     fake_client_socket: UdpSocket,
@@ -88,58 +294,162 @@ pub struct Server {
 impl Server {
 
     ///
-    /// Create a new server instance from the provided
+    /// Create a new single-worker server instance from the provided
     /// [`ServerConfig`](../config/trait.ServerConfig.html) trait object instance.
     ///
     pub fn new(config: Box<ServerConfig>) -> Server {
-        let online_key = OnlineKey::new();
-        let public_key: String;
-
-        let cert_bytes = {
-            let seed = match kms::load_seed(&config) {
-                Ok(seed) => seed,
-                Err(e) => {
-                    error!("Failed to load seed: {:#?}", e);
-                    process::exit(1);
+        let config: Arc<ServerConfig> = Arc::from(config);
+        let metrics = Arc::new(Metrics::new());
+        let keep_running = Arc::new(AtomicBool::new(true));
+
+        // Serve metrics on the single-worker path too, so `metrics_port` is honoured regardless
+        // of which entrypoint (`new` or `spawn_workers`) the binary uses.
+        if let Some(port) = config.metrics_port() {
+            Server::serve_metrics(port, metrics.clone());
+        }
+
+        Server::with_state(config, metrics, keep_running, 0)
+    }
+
+    ///
+    /// Spawn [`num_workers`](../config/trait.ServerConfig.html#tymethod.num_workers) worker
+    /// threads, each binding the same `interface:port` with `SO_REUSEPORT` and running an
+    /// independent event loop. The workers share the response and bad-request counters so the
+    /// STATUS line reports aggregate totals. Returns the shared `keep_running` flag (set it to
+    /// `false` to ask every worker to exit) together with the worker join handles.
+    ///
+    pub fn spawn_workers(config: Box<ServerConfig>) -> (Arc<AtomicBool>, Vec<JoinHandle<()>>) {
+        let config: Arc<ServerConfig> = Arc::from(config);
+        let metrics = Arc::new(Metrics::new());
+        let keep_running = Arc::new(AtomicBool::new(true));
+
+        // The metrics endpoint reports totals aggregated across every worker.
+        if let Some(port) = config.metrics_port() {
+            Server::serve_metrics(port, metrics.clone());
+        }
+
+        let num_workers = config.num_workers();
+        let mut handles = Vec::with_capacity(num_workers);
+
+        for worker_id in 0..num_workers {
+            let config = config.clone();
+            let metrics = metrics.clone();
+            let keep_running = keep_running.clone();
+
+            handles.push(thread::spawn(move || {
+                let mut server = Server::with_state(config, metrics, keep_running, worker_id);
+                while !server.process_events() {}
+            }));
+        }
+
+        (keep_running, handles)
+    }
+
+    // Spawn a background thread serving Prometheus-format metrics at `GET /metrics`.
+    fn serve_metrics(port: u16, metrics: Arc<Metrics>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let listener = TcpListener::bind(("0.0.0.0", port))
+                .expect("failed to bind metrics port");
+
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(mut stream) => {
+                        let mut buf = [0u8; 512];
+                        let n = stream.read(&mut buf).unwrap_or(0);
+                        let body = metrics.render();
+                        let response = if buf[..n].starts_with(b"GET /metrics") {
+                            format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\
+                                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                                body.len(),
+                                body
+                            )
+                        } else {
+                            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+                             Connection: close\r\n\r\n"
+                                .to_string()
+                        };
+                        let _ = stream.write_all(response.as_bytes());
+                    }
+                    Err(e) => warn!("metrics accept error: {:?}", e),
                 }
-            };
-            let mut long_term_key = LongTermKey::new(&seed);
-            public_key = hex::encode(long_term_key.public_key());
+            }
+        })
+    }
 
-            long_term_key.make_cert(&online_key).encode().unwrap()
+    // Build a worker bound to the configured address with `SO_REUSEPORT`, sharing the supplied
+    // counters and shutdown flag with its sibling workers.
+    fn with_state(
+        config: Arc<ServerConfig>,
+        metrics: Arc<Metrics>,
+        keep_running: Arc<AtomicBool>,
+        worker_id: usize,
+    ) -> Server {
+        let online_key = OnlineKey::new();
+
+        let seed = match kms::load_seed(&config) {
+            Ok(seed) => seed,
+            Err(e) => {
+                error!("Failed to load seed: {:#?}", e);
+                process::exit(1);
+            }
+        };
+        // Retained so the rotation timer can re-issue delegation certificates.
+        let mut long_term_key = LongTermKey::new(&seed);
+        let public_key = hex::encode(long_term_key.public_key());
+
+        // Draft clients bind a request to this server by hashing its long-term public key into
+        // the SRV tag; precompute the expected value once for validation.
+        let srv_value = {
+            let mut ctx = digest::Context::new(&digest::SHA512);
+            ctx.update(&[0xff]);
+            ctx.update(long_term_key.public_key().as_ref());
+            Vec::from(&ctx.finish().as_ref()[..32])
         };
 
-        let response_counter = AtomicUsize::new(0);
-        let keep_running = Arc::new(AtomicBool::new(true));
+        // Certificates stay valid for two rotation intervals so that consecutive certs overlap
+        // by one interval, never leaving an in-flight client without a valid delegation.
+        let cert_validity = config.online_key_validity() * 2;
+        let cert_bytes = long_term_key
+            .make_cert(&online_key, cert_validity)
+            .encode()
+            .unwrap();
 
-        let sock_addr = config.socket_addr().expect("");
-        let socket = UdpSocket::bind(&sock_addr).expect("failed to bind to socket");
+        let sock_addr = config.udp_socket_addr().expect("invalid listen address");
+        let socket = Server::bind_reuseport(&sock_addr);
 
         let poll_duration = Some(Duration::from_millis(100));
 
         let mut timer: Timer<()> = Timer::default();
         timer.set_timeout(config.status_interval(), ());
 
+        let mut rotation_timer: Timer<()> = Timer::default();
+        rotation_timer.set_timeout(config.online_key_validity(), ());
+
         let poll = Poll::new().unwrap();
         poll.register(&socket, MESSAGE, Ready::readable(), PollOpt::edge())
             .unwrap();
         poll.register(&timer, STATUS, Ready::readable(), PollOpt::edge())
             .unwrap();
+        poll.register(&rotation_timer, ROTATE, Ready::readable(), PollOpt::edge())
+            .unwrap();
 
         let merkle = MerkleTree::new();
         let requests = Vec::with_capacity(config.batch_size() as usize);
 
         Server {
             config,
+            long_term_key,
             online_key,
             cert_bytes,
 
-            response_counter,
-            num_bad_requests: 0,
+            metrics,
+            worker_id,
             socket,
             keep_running,
             poll_duration,
             timer,
+            rotation_timer,
             poll,
             events: Events::with_capacity(32),
             merkle,
@@ -147,36 +457,125 @@ impl Server {
             buf: [0u8; 65_536],
 
             public_key,
+            srv_value,
 
             #[cfg(fuzzing)]
             fake_client_socket: UdpSocket::bind(&"127.0.0.1:0".parse().unwrap()).unwrap(),
         }
     }
 
+    // Bind a mio `UdpSocket` with `SO_REUSEADDR`/`SO_REUSEPORT` so that every worker can share
+    // the same `interface:port` and have the kernel load-balance datagrams between them.
+    fn bind_reuseport(addr: &SocketAddr) -> UdpSocket {
+        let builder = if addr.is_ipv4() {
+            UdpBuilder::new_v4()
+        } else {
+            UdpBuilder::new_v6()
+        }
+        .expect("failed to create socket builder");
+
+        builder.reuse_address(true).expect("SO_REUSEADDR failed");
+        builder.reuse_port(true).expect("SO_REUSEPORT failed");
+
+        let std_socket = builder.bind(addr).expect("failed to bind to socket");
+        UdpSocket::from_socket(std_socket).expect("failed to create mio socket")
+    }
+
+    // Mint a fresh online key, re-issue its DELE/CERT from the long-term key, and swap it in.
+    // Called from the ROTATE timer between batches, so the swap never races the request path.
+    // The new certificate's DELE validity spans two rotation intervals, so it overlaps the
+    // outgoing certificate by a full interval and no in-flight client is stranded.
+    fn rotate_online_key(&mut self) {
+        let new_online_key = OnlineKey::new();
+        let cert_validity = self.config.online_key_validity() * 2;
+        let new_cert = self
+            .long_term_key
+            .make_cert(&new_online_key, cert_validity)
+            .encode()
+            .unwrap();
+
+        self.cert_bytes = new_cert;
+        self.online_key = new_online_key;
+
+        info!("worker {} rotated online key", self.worker_id);
+    }
+
     /// Returns a reference counted pointer the this server's `keep_running` value.
     pub fn get_keep_running(&self) -> Arc<AtomicBool> {
         return self.keep_running.clone();
     }
 
-    // extract the client's nonce from its request
-    fn nonce_from_request<'a>(&self, buf: &'a [u8], num_bytes: usize) -> Result<&'a [u8], Error> {
+    // extract the client's nonce and negotiated protocol version from its request, detecting
+    // whether legacy (Google) or IETF-draft framing is in use
+    fn nonce_from_request<'a>(
+        &self,
+        buf: &'a [u8],
+        num_bytes: usize,
+    ) -> Result<(&'a [u8], Version), Error> {
         if num_bytes < MIN_REQUEST_LENGTH as usize {
             return Err(Error::RequestTooShort);
         }
 
-        let tag_count = &buf[..4];
-        let expected_nonc = &buf[8..12];
-        let expected_pad = &buf[12..16];
+        // Drop undersized datagrams before doing any work: a request smaller than a response
+        // lets a spoofed-source client use the server for UDP amplification.
+        if num_bytes < self.config.min_request_size() {
+            return Err(Error::RequestTooShort);
+        }
+
+        let datagram = &buf[..num_bytes];
 
-        let tag_count_is_2 = tag_count == [0x02, 0x00, 0x00, 0x00];
-        let tag1_is_nonc = expected_nonc == Tag::NONC.wire_value();
-        let tag2_is_pad = expected_pad == Tag::PAD.wire_value();
+        if datagram.starts_with(IETF_FRAME_MAGIC) {
+            // IETF draft: 8-byte magic, LE u32 length, then the Roughtime message.
+            if num_bytes < IETF_FRAME_MAGIC.len() + 4 {
+                return Err(Error::RequestTooShort);
+            }
+            let declared = LittleEndian::read_u32(&datagram[8..12]) as usize;
+            let msg = &datagram[12..];
+            if declared != msg.len() {
+                return Err(Error::InvalidRequest);
+            }
 
-        if tag_count_is_2 && tag1_is_nonc && tag2_is_pad {
-            Ok(&buf[0x10..0x50])
+            // If the client scoped its request to a server identity, it must match ours.
+            if let Some(srv) = tag_value(msg, Tag::SRV) {
+                if srv != self.srv_value.as_slice() {
+                    return Err(Error::InvalidRequest);
+                }
+            }
+
+            let nonce = self.nonce_tag(msg)?;
+            let version = self.negotiate_version(msg)?;
+            Ok((nonce, version))
         } else {
-            Err(Error::InvalidRequest)
+            // Legacy framing: the Roughtime message begins directly at offset 0.
+            let nonce = self.nonce_tag(datagram)?;
+            Ok((nonce, Version::Classic))
+        }
+    }
+
+    // extract the NONC tag value, enforcing the protocol's fixed 32-byte nonce length
+    fn nonce_tag<'a>(&self, msg: &'a [u8]) -> Result<&'a [u8], Error> {
+        let nonce = tag_value(msg, Tag::NONC).ok_or(Error::InvalidRequest)?;
+        if nonce.len() != 32 {
+            return Err(Error::InvalidRequest);
+        }
+        Ok(nonce)
+    }
+
+    // choose a mutually-supported version from the client's `VER` tag, which lists one or more
+    // 4-byte version words in preference order
+    fn negotiate_version(&self, msg: &[u8]) -> Result<Version, Error> {
+        let ver = tag_value(msg, Tag::VER).ok_or(Error::InvalidRequest)?;
+        if ver.is_empty() || ver.len() % 4 != 0 {
+            return Err(Error::InvalidRequest);
         }
+
+        for word in ver.chunks_exact(4) {
+            if LittleEndian::read_u32(word) == ROUGHTIM_DRAFT_VERSION {
+                return Ok(Version::Ietf(ROUGHTIM_DRAFT_VERSION));
+            }
+        }
+
+        Err(Error::InvalidRequest)
     }
 
     fn make_response(
@@ -185,6 +584,7 @@ impl Server {
         cert_bytes: &[u8],
         path: &[u8],
         idx: u32,
+        version: Version,
     ) -> RtMessage {
         let mut index = [0; 4];
         (&mut index as &mut [u8])
@@ -194,8 +594,20 @@ impl Server {
         let sig_bytes = srep.get_field(Tag::SIG).unwrap();
         let srep_bytes = srep.get_field(Tag::SREP).unwrap();
 
-        let mut response = RtMessage::new(5);
+        // Draft clients receive an extra `VER` tag echoing the negotiated version word.
+        let num_fields = if let Version::Ietf(_) = version { 6 } else { 5 };
+        let mut response = RtMessage::new(num_fields);
+
+        // Tags must be added in strictly-increasing wire order; VER (0x00524556) sorts between
+        // SIG (0x00474953) and PATH (0x48544150).
         response.add_field(Tag::SIG, sig_bytes).unwrap();
+        if let Version::Ietf(word) = version {
+            let mut ver = [0u8; 4];
+            (&mut ver as &mut [u8])
+                .write_u32::<LittleEndian>(word)
+                .unwrap();
+            response.add_field(Tag::VER, &ver).unwrap();
+        }
         response.add_field(Tag::PATH, path).unwrap();
         response.add_field(Tag::SREP, srep_bytes).unwrap();
         response.add_field(Tag::CERT, cert_bytes).unwrap();
@@ -204,6 +616,21 @@ impl Server {
         response
     }
 
+    // wrap an encoded response in the outer IETF framing (magic + LE length) when the client
+    // negotiated the draft protocol; legacy clients receive the bare message
+    fn frame_response(bytes: Vec<u8>, version: Version) -> Vec<u8> {
+        match version {
+            Version::Classic => bytes,
+            Version::Ietf(_) => {
+                let mut framed = Vec::with_capacity(IETF_FRAME_MAGIC.len() + 4 + bytes.len());
+                framed.extend_from_slice(IETF_FRAME_MAGIC);
+                framed.write_u32::<LittleEndian>(bytes.len() as u32).unwrap();
+                framed.extend_from_slice(&bytes);
+                framed
+            }
+        }
+    }
+
     /// The main processing function for incoming connections. This method should be
     /// called repeatedly in a loop to process requests. It returns 'true' when the
     /// server has shutdown (due to keep_running being set to 'false').
@@ -221,18 +648,21 @@ impl Server {
                     'process_batch: loop {
                         check_ctrlc!(self.keep_running);
 
-                        let resp_start = self.response_counter.load(Ordering::SeqCst);
+                        let resp_start = self.metrics.responses.load(Ordering::SeqCst);
 
                         for i in 0..self.config.batch_size() {
                             match self.socket.recv_from(&mut self.buf) {
                                 Ok((num_bytes, src_addr)) => {
                                     match self.nonce_from_request(&self.buf, num_bytes) {
-                                        Ok(nonce) => {
-                                            self.requests.push((Vec::from(nonce), src_addr));
+                                        Ok((nonce, version)) => {
+                                            self.requests
+                                                .push((Vec::from(nonce), src_addr, version));
                                             self.merkle.push_leaf(nonce);
                                         }
                                         Err(e) => {
-                                            self.num_bad_requests += 1;
+                                            self.metrics
+                                                .bad_requests
+                                                .fetch_add(1, Ordering::SeqCst);
 
                                             info!(
                                                 "Invalid request: '{:?}' ({} bytes) from {} (#{} in batch, resp #{})",
@@ -262,22 +692,32 @@ impl Server {
                             break 'process_batch;
                         }
 
+                        self.metrics.batch_fill.observe(self.requests.len() as u64);
+
                         let merkle_root = self.merkle.compute_root();
                         let srep = self.online_key.make_srep(time::get_time(), &merkle_root);
 
-                        for (i, &(ref nonce, ref src_addr)) in self.requests.iter().enumerate() {
+                        for (i, &(ref nonce, ref src_addr, version)) in
+                            self.requests.iter().enumerate()
+                        {
                             let paths = self.merkle.get_paths(i);
 
-                            let resp =
-                                self.make_response(&srep, &self.cert_bytes, &paths, i as u32);
-                            let resp_bytes = resp.encode().unwrap();
+                            let resp = self.make_response(
+                                &srep,
+                                &self.cert_bytes,
+                                &paths,
+                                i as u32,
+                                version,
+                            );
+                            let resp_bytes = Server::frame_response(resp.encode().unwrap(), version);
 
                             let bytes_sent = self
                                 .socket
                                 .send_to(&resp_bytes, &src_addr)
                                 .expect("send_to failed");
+                            self.metrics.response_bytes.observe(bytes_sent as u64);
                             let num_responses =
-                                self.response_counter.fetch_add(1, Ordering::SeqCst);
+                                self.metrics.responses.fetch_add(1, Ordering::SeqCst);
 
                             info!(
                                 "Responded {} bytes to {} for '{}..' (#{} in batch, resp #{})",
@@ -299,15 +739,24 @@ impl Server {
                 }
 
                 STATUS => {
-                    info!(
-                        "responses {}, invalid requests {}",
-                        self.response_counter.load(Ordering::SeqCst),
-                        self.num_bad_requests
-                    );
+                    // Counters are shared across workers, so a single worker logs the aggregate.
+                    if self.worker_id == 0 {
+                        info!(
+                            "responses {}, invalid requests {}",
+                            self.metrics.responses.load(Ordering::SeqCst),
+                            self.metrics.bad_requests.load(Ordering::SeqCst)
+                        );
+                    }
 
                     self.timer.set_timeout(self.config.status_interval(), ());
                 }
 
+                ROTATE => {
+                    self.rotate_online_key();
+                    self.rotation_timer
+                        .set_timeout(self.config.online_key_validity(), ());
+                }
+
                 _ => unreachable!(),
             }
         }
@@ -325,14 +774,14 @@ impl Server {
     }
 
     /// Returns a reference to the `ServerConfig` this server was configured with
-    pub fn get_config(&self) -> &Box<ServerConfig> {
+    pub fn get_config(&self) -> &Arc<ServerConfig> {
         return &self.config;
     }
 
     #[cfg(fuzzing)]
     pub fn send_to_self(&mut self, data: &[u8]) {
-        self.response_counter.store(0, Ordering::SeqCst);;
-        self.num_bad_requests = 0;
+        self.metrics.responses.store(0, Ordering::SeqCst);
+        self.metrics.bad_requests.store(0, Ordering::SeqCst);
         let res = self
             .fake_client_socket
             .send_to(data, &self.socket.local_addr().unwrap());